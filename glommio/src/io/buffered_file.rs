@@ -10,14 +10,58 @@ use crate::{
 };
 use std::{
     cell::Ref,
+    mem::MaybeUninit,
     os::unix::io::{AsRawFd, FromRawFd, RawFd},
     path::{Path, PathBuf},
 };
 
-use super::Stat;
+use super::{buffered_stream::BufferedStream, Stat};
 
 type Result<T> = crate::Result<T, ()>;
 
+thread_local!(static COPY_RANGE_STATS: std::cell::Cell<CopyRangeStats> = std::cell::Cell::new(CopyRangeStats::default()));
+
+fn record_copy_range(bytes: u64, fallback: bool) {
+    COPY_RANGE_STATS.with(|s| {
+        let mut stats = s.get();
+        if fallback {
+            stats.fallback_copies += 1;
+            stats.fallback_bytes += bytes;
+        } else {
+            stats.kernel_copies += 1;
+            stats.kernel_bytes += bytes;
+        }
+        s.set(stats);
+    });
+}
+
+/// Aggregate (count, bytes) counters for [`BufferedFile::copy_range`] on
+/// this shard, broken out by whether the copy went through the kernel's
+/// `copy_file_range(2)` fast path or the chunked userspace fallback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyRangeStats {
+    /// Number of `copy_range` calls completed via `copy_file_range(2)`,
+    /// counted once per call however many syscalls the call issued.
+    pub kernel_copies: u64,
+    /// Total bytes copied via `copy_file_range(2)`.
+    pub kernel_bytes: u64,
+    /// Number of `copy_range` calls that fell back to a
+    /// `read_buffered`/`write_buffered` loop.
+    pub fallback_copies: u64,
+    /// Total bytes copied via the userspace fallback.
+    pub fallback_bytes: u64,
+}
+
+/// Returns the current [`CopyRangeStats`] for this shard.
+///
+/// These aren't folded into `crate::executor().io_stats()` yet (that's the
+/// natural home for them, alongside the other per-shard I/O counters it
+/// already reports); until that plumbing lands, this free function is the
+/// only way to read them.
+pub fn copy_range_stats() -> CopyRangeStats {
+    COPY_RANGE_STATS.with(|s| s.get())
+}
+
 /// An asynchronously accessed file backed by the OS page cache.
 ///
 /// All access uses buffered I/O, and all operations including open and close
@@ -186,6 +230,150 @@ impl BufferedFile {
         Ok(ReadResult::from_sliced_buffer(source, 0, read_size))
     }
 
+    /// Reads data at the specified position directly into a
+    /// caller-provided, possibly-uninitialized buffer, returning how many
+    /// bytes the kernel actually initialized.
+    ///
+    /// Callers in hot read loops (e.g. recycling one scratch buffer, or an
+    /// arena/slab allocator) can reuse the same `buf` across many reads
+    /// instead of receiving a fresh [`ReadResult`] each time.
+    /// `buf` never needs to be pre-zeroed; only the returned number of
+    /// bytes should be treated as initialized.
+    ///
+    /// `read_at_into` shares `read_at`'s `read_buffered` reactor path and
+    /// `file_buffered_reads` accounting: the kernel reads into a
+    /// library-allocated buffer exactly as it does for [`read_at`], and the
+    /// completed read is then copied into `buf`. That per-call allocation
+    /// is therefore still made. It is implemented independently rather
+    /// than routing `read_at`'s zero-copy path through an extra memcpy.
+    ///
+    /// [`read_at`]: #method.read_at
+    pub async fn read_at_into(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let source = self.file.reactor.upgrade().unwrap().read_buffered(
+            self.as_raw_fd(),
+            pos,
+            buf.len(),
+            self.file.scheduler.borrow().as_ref(),
+        );
+        let read_size = source.collect_rw().await.map_err(|source| {
+            GlommioError::create_enhanced(
+                source,
+                "Reading",
+                self.file.path.borrow().as_ref(),
+                Some(self.as_raw_fd()),
+            )
+        })?;
+        let result = ReadResult::from_sliced_buffer(source, 0, read_size);
+        // SAFETY: `result` holds exactly `read_size` bytes initialized by
+        // the kernel; copying them into `buf` initializes only that many
+        // leading elements, matching the count we return.
+        unsafe {
+            std::ptr::copy_nonoverlapping(result.as_ptr(), buf.as_mut_ptr() as *mut u8, read_size);
+        }
+        Ok(read_size)
+    }
+
+    /// Copies `len` bytes starting at `src_offset` in this file to `dst` at
+    /// `dst_offset`, offloading the copy to the kernel via the Linux
+    /// `copy_file_range(2)` syscall, the way `std::io::copy` specializes to
+    /// a kernel-side copy on Linux. On filesystems that support it
+    /// (reflink/CoW filesystems such as XFS or Btrfs) this avoids bouncing
+    /// the data through userspace entirely.
+    ///
+    /// `copy_file_range` is allowed to copy fewer bytes than requested in a
+    /// single call, and returns 0 once `src` is exhausted, so this loops
+    /// until `len` bytes have been copied or the source hits EOF, returning
+    /// the number of bytes actually copied.
+    ///
+    /// When the kernel reports `EXDEV`, `ENOSYS` or `EOPNOTSUPP` (crossing
+    /// filesystems, or no kernel/filesystem support), this transparently
+    /// falls back to a chunked `read_buffered`/`write_buffered` loop
+    /// between the two files so callers don't need to special-case it.
+    pub async fn copy_range(
+        &self,
+        src_offset: u64,
+        dst: &BufferedFile,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64> {
+        match self.copy_range_kernel(src_offset, dst, dst_offset, len).await {
+            Ok(copied) => {
+                record_copy_range(copied, false);
+                Ok(copied)
+            }
+            Err(source)
+                if matches!(
+                    source.raw_os_error(),
+                    Some(libc::EXDEV) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+                ) =>
+            {
+                let copied = self.copy_range_fallback(src_offset, dst, dst_offset, len).await?;
+                record_copy_range(copied, true);
+                Ok(copied)
+            }
+            Err(source) => Err(GlommioError::create_enhanced(
+                source,
+                "Copying",
+                self.file.path.borrow().as_ref(),
+                Some(self.as_raw_fd()),
+            )),
+        }
+    }
+
+    async fn copy_range_kernel(
+        &self,
+        mut src_offset: u64,
+        dst: &BufferedFile,
+        mut dst_offset: u64,
+        len: u64,
+    ) -> std::io::Result<u64> {
+        let reactor = self.file.reactor.upgrade().unwrap();
+        let mut remaining = len;
+        let mut copied = 0u64;
+        while remaining > 0 {
+            let chunk = remaining.min(u32::MAX as u64) as u32;
+            let source =
+                reactor.copy_file_range(self.as_raw_fd(), src_offset, dst.as_raw_fd(), dst_offset, chunk);
+            let n = source.collect_rw().await?;
+            if n == 0 {
+                break;
+            }
+            copied += n as u64;
+            remaining -= n as u64;
+            src_offset += n as u64;
+            dst_offset += n as u64;
+        }
+        Ok(copied)
+    }
+
+    async fn copy_range_fallback(
+        &self,
+        mut src_offset: u64,
+        dst: &BufferedFile,
+        mut dst_offset: u64,
+        len: u64,
+    ) -> Result<u64> {
+        const CHUNK_SIZE: usize = 128 << 10;
+        let mut remaining = len;
+        let mut copied = 0u64;
+        while remaining > 0 {
+            let want = remaining.min(CHUNK_SIZE as u64) as usize;
+            let buf = self.read_at(src_offset, want).await?;
+            if buf.is_empty() {
+                break;
+            }
+            let n = dst.write_at(buf.to_vec(), dst_offset).await? as u64;
+            if n == 0 {
+                break;
+            }
+            copied += n;
+            remaining -= n;
+            src_offset += n;
+            dst_offset += n;
+        }
+        Ok(copied)
+    }
+
     /// Issues `fdatasync` for the underlying file, instructing the OS to flush
     /// all writes to the device, providing durability even if the system
     /// crashes or is rebooted.
@@ -255,6 +443,21 @@ impl BufferedFile {
         self.file.statx().await.map(Into::into)
     }
 
+    /// Wraps this file in a [`BufferedStream`], giving it a logical cursor
+    /// and adapting it to the [`futures_lite::io::AsyncRead`],
+    /// [`AsyncWrite`](futures_lite::io::AsyncWrite) and
+    /// [`AsyncSeek`](futures_lite::io::AsyncSeek) traits.
+    ///
+    /// Most callers reach for this when they already hold something generic
+    /// over `AsyncRead`/`AsyncWrite` (a parser, an encoder, a copy routine)
+    /// and would otherwise have to hand-roll a cursor on top of
+    /// `read_at`/`write_at` themselves.
+    ///
+    /// [`BufferedStream`]: struct.BufferedStream.html
+    pub fn stream(self) -> BufferedStream {
+        BufferedStream::new(std::rc::Rc::new(self))
+    }
+
     /// Closes this file.
     pub async fn close(self) -> Result<()> {
         self.file.close().await
@@ -366,6 +569,91 @@ mod test {
         assert_eq!(stats.all_rings().file_buffered_writes(), (1, 6));
     });
 
+    buffered_file_test!(read_at_into_reuses_caller_buffer, path, _k, {
+        let writer = BufferedFile::create(path.join("testfile")).await.unwrap();
+        let reader = BufferedFile::open(path.join("testfile")).await.unwrap();
+
+        let wb = vec![0, 1, 2, 3, 4, 5];
+        writer.write_at(wb, 0).await.unwrap();
+
+        // Oversized and uninitialized on purpose: read_at_into must not
+        // require the caller to zero it first, and must report only the
+        // bytes the kernel actually filled in.
+        let mut scratch = [std::mem::MaybeUninit::<u8>::uninit(); 16];
+        let n = reader.read_at_into(0, &mut scratch).await.unwrap();
+        assert_eq!(n, 6);
+        let first: [u8; 16] = unsafe { std::mem::transmute(scratch) };
+        check_contents!(first[0..n], 0);
+
+        // The same buffer can be recycled for a second read at a different
+        // position without reallocating.
+        let mut scratch: [std::mem::MaybeUninit<u8>; 16] = unsafe { std::mem::transmute(first) };
+        let n = reader.read_at_into(3, &mut scratch).await.unwrap();
+        assert_eq!(n, 3);
+        let second: [u8; 16] = unsafe { std::mem::transmute(scratch) };
+        check_contents!(second[0..n], 3);
+
+        writer.close().await.unwrap();
+        reader.close().await.unwrap();
+    });
+
+    buffered_file_test!(copy_range_kernel_path, path, _k, {
+        let src = BufferedFile::create(path.join("src")).await.unwrap();
+        let dst = BufferedFile::create(path.join("dst")).await.unwrap();
+
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        src.write_at(data, 0).await.unwrap();
+
+        let before = super::copy_range_stats();
+        let copied = src.copy_range(10, &dst, 0, 100).await.unwrap();
+        assert_eq!(copied, 100);
+        let after = super::copy_range_stats();
+        assert_eq!(after.kernel_copies, before.kernel_copies + 1);
+        assert_eq!(after.kernel_bytes, before.kernel_bytes + 100);
+        assert_eq!(after.fallback_copies, before.fallback_copies);
+
+        let rb = dst.read_at(0, 100).await.unwrap();
+        assert_eq!(rb.len(), 100);
+        check_contents!(*rb, 10);
+
+        src.close().await.unwrap();
+        dst.close().await.unwrap();
+    });
+
+    buffered_file_test!(copy_range_fallback_loop, path, _k, {
+        // Exercises the userspace fallback loop directly: real EXDEV/ENOSYS
+        // conditions require crossing filesystems or kernels that lack
+        // copy_file_range, which isn't reliably reproducible in a test
+        // environment, but the loop itself (chunking, EOF handling, offset
+        // bookkeeping) is plain read_buffered/write_buffered and can be
+        // verified in isolation.
+        let src = BufferedFile::create(path.join("src")).await.unwrap();
+        let dst = BufferedFile::create(path.join("dst")).await.unwrap();
+
+        let data: Vec<u8> = (0..50).map(|i| i as u8).collect();
+        src.write_at(data, 0).await.unwrap();
+
+        let before = super::copy_range_stats();
+        let copied = src.copy_range_fallback(5, &dst, 0, 40).await.unwrap();
+        assert_eq!(copied, 40);
+        super::record_copy_range(copied, true);
+        let after = super::copy_range_stats();
+        assert_eq!(after.fallback_copies, before.fallback_copies + 1);
+        assert_eq!(after.fallback_bytes, before.fallback_bytes + 40);
+
+        let rb = dst.read_at(0, 40).await.unwrap();
+        assert_eq!(rb.len(), 40);
+        check_contents!(*rb, 5);
+
+        // Copying past EOF stops early and reports what was actually
+        // copied, just like the kernel path's own EOF behavior.
+        let copied = src.copy_range_fallback(40, &dst, 0, 40).await.unwrap();
+        assert_eq!(copied, 10);
+
+        src.close().await.unwrap();
+        dst.close().await.unwrap();
+    });
+
     buffered_file_test!(write_past_end, path, _k, {
         let writer = BufferedFile::create(path.join("testfile")).await.unwrap();
 
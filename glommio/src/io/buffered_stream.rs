@@ -0,0 +1,380 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2020 Datadog, Inc.
+//
+
+use crate::io::buffered_file::BufferedFile;
+use futures_lite::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite},
+    ready,
+};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+/// The size of the internal buffer used by [`BufferedStream`] to turn
+/// positional `read_at`/`write_at` calls into a sequential stream.
+const DEFAULT_BUFFER_SIZE: usize = 128 << 10;
+
+type ReadFut = Pin<Box<dyn Future<Output = crate::Result<crate::io::ReadResult, ()>>>>;
+type WriteFut = Pin<Box<dyn Future<Output = crate::Result<usize, ()>>>>;
+
+enum State {
+    Idle,
+    Reading(ReadFut),
+    Writing(WriteFut),
+}
+
+/// A sequential, cursor-based view over a [`BufferedFile`] that implements
+/// [`futures_lite::io::AsyncRead`], [`AsyncWrite`] and [`AsyncSeek`].
+///
+/// `BufferedFile` only exposes positional `read_at`/`write_at`; there is no
+/// notion of "the next byte" to hand to a parser or encoder expecting a
+/// plain byte stream. `BufferedStream` adapts it by keeping a logical
+/// cursor and a reusable intermediate buffer: sequential reads pull one
+/// chunk at a time from the cursor position and serve subsequent small
+/// reads out of the leftover tail instead of issuing a syscall each time,
+/// while writes accumulate into the buffer and are flushed through
+/// `write_buffered` once it fills up, on an explicit `poll_flush`, or on
+/// close.
+///
+/// Dropping a `BufferedStream` with unflushed writes logs a warning: unlike
+/// `std::fs::File`, glommio's `close`/flush are asynchronous and cannot be
+/// driven to completion from `Drop`, so callers must `poll_close` (or
+/// `futures_lite::AsyncWriteExt::close`) explicitly.
+///
+/// Use [`BufferedFile::stream`] to create one.
+pub struct BufferedStream {
+    file: Rc<BufferedFile>,
+    cursor: u64,
+    /// For reads: the leftover tail of the last chunk pulled from the file,
+    /// along with how much of it has already been consumed. For writes:
+    /// bytes accumulated since the last flush.
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    dirty: bool,
+    max_buffer_size: usize,
+    state: State,
+}
+
+impl BufferedStream {
+    pub(crate) fn new(file: Rc<BufferedFile>) -> BufferedStream {
+        BufferedStream {
+            file,
+            cursor: 0,
+            buffer: Vec::new(),
+            buffer_pos: 0,
+            dirty: false,
+            max_buffer_size: DEFAULT_BUFFER_SIZE,
+            state: State::Idle,
+        }
+    }
+
+    fn unread(&self) -> usize {
+        self.buffer.len().saturating_sub(self.buffer_pos)
+    }
+}
+
+impl AsyncRead for BufferedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.dirty {
+            // `buffer` holds pending writes rather than a read tail; push
+            // them to the file before reading from the cursor.
+            ready!(Pin::new(&mut *this).poll_flush(cx))?;
+        }
+
+        if this.unread() == 0 {
+            if let State::Idle = this.state {
+                let file = this.file.clone();
+                let pos = this.cursor;
+                let size = out.len().max(this.max_buffer_size);
+                this.state = State::Reading(Box::pin(async move { file.read_at(pos, size).await }));
+            }
+
+            if let State::Reading(fut) = &mut this.state {
+                let result = ready!(fut.as_mut().poll(cx));
+                this.state = State::Idle;
+                let data = result.map_err(io::Error::from)?;
+                this.buffer.clear();
+                this.buffer.extend_from_slice(&data);
+                this.buffer_pos = 0;
+                // EOF: an empty chunk at the cursor means there is nothing
+                // left to read, consistent with `BufferedFile::read_at`'s
+                // own EOF behavior of returning a zero-length result.
+                if this.buffer.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+            } else {
+                unreachable!()
+            }
+        }
+
+        let available = &this.buffer[this.buffer_pos..];
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        this.buffer_pos += n;
+        this.cursor += n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for BufferedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if let State::Writing(_) = &this.state {
+            // A previous flush is still draining (possibly across more
+            // than one partial write); let `poll_flush` own the retry loop
+            // rather than duplicating it here.
+            return match Pin::new(&mut *this).poll_flush(cx) {
+                Poll::Ready(Ok(())) => Pin::new(this).poll_write(cx, data),
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            };
+        }
+
+        if !this.dirty {
+            // Whatever is in `buffer` is a cached read tail, not pending
+            // writes; drop it so new data is written at the cursor.
+            this.buffer.clear();
+            this.buffer_pos = 0;
+        }
+
+        if this.buffer.len() >= this.max_buffer_size {
+            return match Pin::new(&mut *this).poll_flush(cx) {
+                Poll::Ready(Ok(())) => Pin::new(this).poll_write(cx, data),
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            };
+        }
+
+        let n = data.len().min(this.max_buffer_size - this.buffer.len());
+        this.buffer.extend_from_slice(&data[..n]);
+        this.dirty = true;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if let State::Writing(fut) = &mut this.state {
+            let result = ready!(fut.as_mut().poll(cx));
+            this.state = State::Idle;
+            let written = result.map_err(io::Error::from)?;
+            this.cursor += written as u64;
+            this.buffer_pos += written;
+
+            if written == 0 && this.buffer_pos < this.buffer.len() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "BufferedFile::write_at wrote 0 bytes flushing a buffered write",
+                )));
+            }
+            if this.buffer_pos < this.buffer.len() {
+                // `write_at` is allowed to make less progress than
+                // requested (e.g. the device is full); resubmit the
+                // unwritten tail instead of declaring the flush done and
+                // silently dropping it, the way `copy_range`'s own fallback
+                // loop keeps going until its whole range is copied.
+                return Pin::new(this).poll_flush(cx);
+            }
+
+            this.buffer.clear();
+            this.buffer_pos = 0;
+            this.dirty = false;
+            return Poll::Ready(Ok(()));
+        }
+
+        // A clean buffer is only a cached read tail: nothing to write back.
+        if !this.dirty || this.buffer.is_empty() {
+            this.dirty = false;
+            return Poll::Ready(Ok(()));
+        }
+
+        let file = this.file.clone();
+        let pos = this.cursor;
+        let data = this.buffer[this.buffer_pos..].to_vec();
+        this.state = State::Writing(Box::pin(async move { file.write_at(data, pos).await }));
+        Pin::new(this).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for BufferedStream {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: io::SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+
+        if this.dirty {
+            // Mirror `BufWriter::seek`: repositioning must not silently
+            // discard buffered-but-unflushed writes, so flush them through
+            // to the file first.
+            match ready!(Pin::new(&mut *this).poll_flush(cx)) {
+                Ok(()) => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+
+        if !matches!(this.state, State::Idle) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot seek a BufferedStream while a read or write is in flight",
+            )));
+        }
+
+        // The write buffer is now guaranteed empty (either it always was,
+        // or the flush above drained it); only the cached read tail is left
+        // to discard.
+        this.buffer.clear();
+        this.buffer_pos = 0;
+
+        this.cursor = match pos {
+            io::SeekFrom::Start(n) => n,
+            io::SeekFrom::Current(n) => {
+                if n >= 0 {
+                    this.cursor.saturating_add(n as u64)
+                } else {
+                    this.cursor.saturating_sub((-n) as u64)
+                }
+            }
+            io::SeekFrom::End(_) => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "seeking from the end of a BufferedStream requires a file_size() lookup; \
+                     resolve the absolute offset and seek with SeekFrom::Start instead",
+                )))
+            }
+        };
+        Poll::Ready(Ok(this.cursor))
+    }
+}
+
+impl Drop for BufferedStream {
+    fn drop(&mut self) {
+        if self.dirty || matches!(self.state, State::Writing(_)) {
+            log::warn!(
+                "BufferedStream dropped with unflushed writes; glommio's close/flush are \
+                 asynchronous and cannot run from Drop, so the pending data was discarded. \
+                 Call `poll_close`/`AsyncWriteExt::close` before dropping."
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{io::BufferedFile, test_utils::make_test_directories};
+    use futures_lite::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    macro_rules! buffered_stream_test {
+        ( $name:ident, $dir:ident, $kind:ident, $code:block) => {
+            #[test]
+            fn $name() {
+                for dir in make_test_directories(&format!("buffered-stream-{}", stringify!($name))) {
+                    let $dir = dir.path.clone();
+                    let $kind = dir.kind;
+                    test_executor!(async move { $code });
+                }
+            }
+        };
+    }
+
+    buffered_stream_test!(write_then_read_back, path, _k, {
+        let file = BufferedFile::create(path.join("testfile")).await.unwrap();
+        let mut stream = file.stream();
+        stream.write_all(b"hello world").await.unwrap();
+        stream.close().await.unwrap();
+
+        let file = BufferedFile::open(path.join("testfile")).await.unwrap();
+        let mut stream = file.stream();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello world");
+        stream.close().await.unwrap();
+    });
+
+    buffered_stream_test!(seek_repositions_cursor, path, _k, {
+        let data: Vec<u8> = (0..50).collect();
+        let file = BufferedFile::create(path.join("testfile")).await.unwrap();
+        file.write_at(data, 0).await.unwrap();
+        file.close().await.unwrap();
+
+        let file = BufferedFile::open(path.join("testfile")).await.unwrap();
+        let mut stream = file.stream();
+
+        let mut buf = [0u8; 10];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf.to_vec(), (0..10).collect::<Vec<u8>>());
+
+        stream.seek(io::SeekFrom::Start(20)).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [20, 21, 22, 23, 24]);
+
+        stream.seek(io::SeekFrom::Current(5)).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [30, 31, 32, 33, 34]);
+
+        stream.close().await.unwrap();
+    });
+
+    buffered_stream_test!(seek_flushes_dirty_buffer_first, path, _k, {
+        let file = BufferedFile::create(path.join("testfile")).await.unwrap();
+        let mut stream = file.stream();
+
+        // Buffered but not yet flushed: seeking must not silently drop
+        // these bytes.
+        stream.write_all(&[1, 2, 3, 4, 5]).await.unwrap();
+        stream.seek(io::SeekFrom::Start(0)).await.unwrap();
+        stream.close().await.unwrap();
+
+        let file = BufferedFile::open(path.join("testfile")).await.unwrap();
+        let rb = file.read_at(0, 5).await.unwrap();
+        assert_eq!(&*rb, &[1, 2, 3, 4, 5]);
+        file.close().await.unwrap();
+    });
+
+    buffered_stream_test!(write_spanning_multiple_internal_flushes, path, _k, {
+        // Forces at least one implicit flush inside `poll_write` itself
+        // (buffer fills mid-write and must be drained before the rest of
+        // the caller's data is buffered), which is the path that must keep
+        // advancing the logical cursor on every completed flush.
+        let size = DEFAULT_BUFFER_SIZE * 2 + 123;
+        let data: Vec<u8> = (0..size).map(|i| (i % 256) as u8).collect();
+
+        let file = BufferedFile::create(path.join("testfile")).await.unwrap();
+        let mut stream = file.stream();
+        stream.write_all(&data).await.unwrap();
+        stream.close().await.unwrap();
+
+        let file = BufferedFile::open(path.join("testfile")).await.unwrap();
+        let mut stream = file.stream();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+        stream.close().await.unwrap();
+    });
+}
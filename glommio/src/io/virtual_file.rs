@@ -0,0 +1,580 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2020 Datadog, Inc.
+//
+
+use crate::{
+    io::{glommio_file::GlommioFile, read_result::ReadResult, OpenOptions, Stat},
+    GlommioError,
+};
+use std::{
+    cell::{Cell, RefCell},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+type Result<T> = crate::Result<T, ()>;
+
+/// The number of file descriptors a [`VirtualFilePool`] keeps open at once
+/// when [`configure_max_open_files`] has not been called on the shard.
+const DEFAULT_MAX_OPEN_FILES: usize = 1024;
+
+thread_local!(static MAX_OPEN_FILES: Cell<usize> = Cell::new(DEFAULT_MAX_OPEN_FILES));
+
+/// Sets the maximum number of file descriptors this shard's
+/// [`VirtualFilePool`] will keep open at once, evicting with CLOCK once the
+/// cap is reached.
+///
+/// Wiring this into `LocalExecutorBuilder` so it can be set at executor
+/// construction time, the way other per-shard knobs are, is tracked
+/// separately and not done yet: this function is the whole of the public
+/// API for now, and must be called directly before the first `VirtualFile`
+/// is opened on this shard, since the pool is sized once, lazily, on first
+/// use, and is not resized afterwards.
+pub fn configure_max_open_files(max_open_files: usize) {
+    MAX_OPEN_FILES.with(|m| m.set(max_open_files.max(1)));
+}
+
+/// Open/reopen/evict counters for this shard's [`VirtualFilePool`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VirtualFileStats {
+    /// Number of descriptors opened for the first time.
+    pub files_opened: u64,
+    /// Number of descriptors reopened after being evicted from the pool.
+    pub files_reopened: u64,
+    /// Number of descriptors evicted by the CLOCK algorithm to make room
+    /// for another file.
+    pub files_evicted: u64,
+}
+
+/// Returns the current open/reopen/evict counters for this shard's
+/// [`VirtualFilePool`].
+///
+/// These aren't folded into `crate::executor().io_stats()` yet (that would
+/// make `VirtualFile` counted alongside `BufferedFile`/`DmaFile` the way
+/// callers would expect); until that plumbing lands, this free function is
+/// the only way to read them.
+pub fn virtual_file_stats() -> VirtualFileStats {
+    let (files_opened, files_reopened, files_evicted) = executor_pool().pool_stats();
+    VirtualFileStats {
+        files_opened,
+        files_reopened,
+        files_evicted,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    /// The file currently occupying this slot, if any.
+    owner: Option<Rc<Inner>>,
+    /// CLOCK "recently used" bit, set on every access and cleared by the
+    /// sweeping hand as it looks for a victim.
+    referenced: bool,
+}
+
+impl Slot {
+    /// A slot is pinned (ineligible for eviction) while its owner has an
+    /// I/O operation suspended across an `.await` on the fd it holds;
+    /// closing the fd out from under that operation would let an unrelated
+    /// concurrent `open()` elsewhere in the process reuse the descriptor
+    /// number, so the in-flight read/write could silently hit the wrong
+    /// file instead of just erroring out.
+    fn pinned(&self) -> bool {
+        self.owner.as_ref().map_or(false, |o| o.inflight.get() > 0)
+    }
+}
+
+/// A per-executor pool of open file descriptors shared by every
+/// [`VirtualFile`] created on the shard.
+///
+/// The pool evicts descriptors with the CLOCK (second-chance) algorithm:
+/// slots are arranged in a ring with a sweeping hand. When a new descriptor
+/// is needed and the ring is full, the hand walks forward clearing the
+/// `referenced` bit of every slot it passes; the first slot it finds
+/// already cleared is closed (asynchronously) and handed to the newcomer.
+/// This keeps recently touched files resident while still bounding the
+/// number of simultaneously open descriptors, which matters for workloads
+/// (LSM/SSTable engines, in particular) that may track tens of thousands of
+/// files against a shard whose `RLIMIT_NOFILE` can't grow to match.
+#[derive(Debug)]
+pub(crate) struct VirtualFilePool {
+    slots: RefCell<Vec<Slot>>,
+    hand: Cell<usize>,
+    max_open_files: usize,
+    opened: Cell<u64>,
+    reopened: Cell<u64>,
+    evicted: Cell<u64>,
+}
+
+impl VirtualFilePool {
+    pub(crate) fn new(max_open_files: usize) -> Rc<VirtualFilePool> {
+        let max_open_files = max_open_files.max(1);
+        Rc::new(VirtualFilePool {
+            slots: RefCell::new(Vec::with_capacity(max_open_files)),
+            hand: Cell::new(0),
+            max_open_files,
+            opened: Cell::new(0),
+            reopened: Cell::new(0),
+            evicted: Cell::new(0),
+        })
+    }
+
+    /// (files opened, files reopened after eviction, files evicted). Backs
+    /// the public [`virtual_file_stats`] function.
+    pub(crate) fn pool_stats(&self) -> (u64, u64, u64) {
+        (self.opened.get(), self.reopened.get(), self.evicted.get())
+    }
+
+    fn touch(&self, slot: usize) {
+        if let Some(s) = self.slots.borrow_mut().get_mut(slot) {
+            s.referenced = true;
+        }
+    }
+
+    /// Finds room for `owner`, evicting and closing a victim with CLOCK if
+    /// the pool is already at capacity.
+    ///
+    /// Slots pinned by an in-flight operation (see [`Slot::pinned`]) are
+    /// skipped by the sweeping hand entirely, as if they didn't exist. If a
+    /// full lap finds nothing else to evict, this yields and sweeps again:
+    /// every pinned operation eventually completes and unpins its slot, so
+    /// this always makes progress without needing to pick an unsafe victim.
+    async fn make_room_for(self: &Rc<Self>, owner: &Rc<Inner>) -> usize {
+        loop {
+            let found = {
+                let mut slots = self.slots.borrow_mut();
+                if slots.len() < self.max_open_files {
+                    slots.push(Slot {
+                        owner: Some(owner.clone()),
+                        referenced: true,
+                    });
+                    return slots.len() - 1;
+                }
+
+                let len = slots.len();
+                let mut result = None;
+                for _ in 0..len {
+                    let hand = self.hand.get();
+                    self.hand.set((hand + 1) % len);
+                    let slot = &mut slots[hand];
+                    if slot.pinned() {
+                        continue;
+                    }
+                    if slot.referenced {
+                        slot.referenced = false;
+                        continue;
+                    }
+                    let victim = slot.owner.replace(owner.clone());
+                    slot.referenced = true;
+                    result = Some((hand, victim));
+                    break;
+                }
+                result
+            };
+
+            let (slot, victim) = match found {
+                Some(found) => found,
+                // Every slot is either pinned or was just un-referenced;
+                // give the in-flight operations a chance to finish and
+                // sweep again.
+                None => {
+                    futures_lite::future::yield_now().await;
+                    continue;
+                }
+            };
+
+            if let Some(victim) = victim {
+                victim.slot.set(None);
+                self.evicted.set(self.evicted.get() + 1);
+                if let Some(file) = victim.file.borrow_mut().take() {
+                    let _ = file.close().await;
+                }
+            }
+            return slot;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+    file: RefCell<Option<GlommioFile>>,
+    slot: Cell<Option<usize>>,
+    pool: Rc<VirtualFilePool>,
+    /// Count of `read_at`/`write_at`/`stat` calls currently suspended across
+    /// an `.await` on this file's fd. While non-zero, `make_room_for` must
+    /// not pick this file's slot as a CLOCK victim.
+    inflight: Cell<u32>,
+}
+
+impl Inner {
+    /// Marks one I/O operation as in flight on this file for as long as the
+    /// returned guard is alive, pinning its slot against CLOCK eviction.
+    fn begin_io(self: &Rc<Self>) -> InflightGuard {
+        self.inflight.set(self.inflight.get() + 1);
+        InflightGuard { inner: self.clone() }
+    }
+}
+
+struct InflightGuard {
+    inner: Rc<Inner>,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.inner.inflight.set(self.inner.inflight.get() - 1);
+    }
+}
+
+fn executor_pool() -> Rc<VirtualFilePool> {
+    // Each shard lazily creates its own pool, sized once on first use by
+    // whatever `configure_max_open_files` last set; absent that
+    // configuration, `DEFAULT_MAX_OPEN_FILES` is used.
+    thread_local!(static POOL: RefCell<Option<Rc<VirtualFilePool>>> = RefCell::new(None));
+    POOL.with(|p| {
+        p.borrow_mut()
+            .get_or_insert_with(|| VirtualFilePool::new(MAX_OPEN_FILES.with(|m| m.get())))
+            .clone()
+    })
+}
+
+/// A file handle that behaves like [`BufferedFile`] but does not keep an
+/// operating system file descriptor open for its entire lifetime.
+///
+/// Every shard maintains a [`VirtualFilePool`] with a fixed cap on
+/// simultaneously open descriptors, set with [`configure_max_open_files`].
+/// A `VirtualFile` only remembers the [`PathBuf`] and open flags/mode it
+/// was created with; the underlying fd is opened lazily and may be
+/// transparently closed and reopened by the pool's CLOCK eviction between
+/// accesses. This makes it practical for a shard to track tens of
+/// thousands of files (as in LSM/SSTable storage engines) without
+/// exhausting the process' `RLIMIT_NOFILE`.
+///
+/// Because [`BufferedFile`] is purely positional, with no seek cursor to
+/// restore, reopening a `VirtualFile` after eviction costs only the
+/// `openat` syscall: there is no other state to recreate.
+///
+/// A slot is never chosen as a CLOCK victim while one of its `VirtualFile`
+/// clones has a `read_at`/`write_at`/`stat` in flight on it, so a
+/// concurrent eviction can never close the fd out from under an
+/// in-progress operation.
+///
+/// [`BufferedFile`]: struct.BufferedFile.html
+///
+/// See the module-level [documentation](index.html) for more details.
+#[derive(Debug, Clone)]
+pub struct VirtualFile {
+    inner: Rc<Inner>,
+}
+
+impl VirtualFile {
+    /// Similar to [`BufferedFile::open`], but the returned file does not
+    /// hold its descriptor open between accesses.
+    ///
+    /// [`BufferedFile::open`]: struct.BufferedFile.html#method.open
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<VirtualFile> {
+        VirtualFile::with_options(path, OpenOptions::new().read(true)).await
+    }
+
+    /// Similar to [`BufferedFile::create`], but the returned file does not
+    /// hold its descriptor open between accesses. The file is opened for
+    /// both reading and writing.
+    ///
+    /// [`BufferedFile::create`]: struct.BufferedFile.html#method.create
+    pub async fn create<P: AsRef<Path>>(path: P) -> Result<VirtualFile> {
+        VirtualFile::with_options(
+            path,
+            OpenOptions::new().read(true).write(true).create(true).truncate(true),
+        )
+        .await
+    }
+
+    async fn with_options<P: AsRef<Path>>(path: P, opts: &OpenOptions) -> Result<VirtualFile> {
+        let flags = libc::O_CLOEXEC
+            | opts.get_access_mode()?
+            | opts.get_creation_mode()?
+            | (opts.custom_flags as libc::c_int & !libc::O_ACCMODE);
+
+        let inner = Rc::new(Inner {
+            path: path.as_ref().to_owned(),
+            flags,
+            mode: opts.mode,
+            file: RefCell::new(None),
+            slot: Cell::new(None),
+            pool: executor_pool(),
+            inflight: Cell::new(0),
+        });
+
+        // Open eagerly so creation failures (e.g. ENOENT) surface the same
+        // way they do for `BufferedFile::open`/`create`, rather than being
+        // deferred to the first `read_at`/`write_at`.
+        let file = VirtualFile { inner };
+        file.ensure_open().await?;
+        Ok(file)
+    }
+
+    async fn ensure_open(&self) -> Result<()> {
+        if self.inner.file.borrow().is_some() {
+            if let Some(slot) = self.inner.slot.get() {
+                self.inner.pool.touch(slot);
+            }
+            return Ok(());
+        }
+
+        let reopening = self.inner.slot.get().is_some();
+        let slot = self.inner.pool.make_room_for(&self.inner).await;
+        self.inner.slot.set(Some(slot));
+
+        let file = GlommioFile::open_at(libc::AT_FDCWD, &self.inner.path, self.inner.flags, self.inner.mode)
+            .await
+            .map_err(|source| {
+                GlommioError::create_enhanced(source, "Opening", Some(self.inner.path.as_path()), None)
+            })?;
+        *self.inner.file.borrow_mut() = Some(file);
+
+        if reopening {
+            self.inner.pool.reopened.set(self.inner.pool.reopened.get() + 1);
+        } else {
+            self.inner.pool.opened.set(self.inner.pool.opened.get() + 1);
+        }
+        Ok(())
+    }
+
+    /// Write the data in the buffer `buf` to this `VirtualFile` at the
+    /// specified position, reopening the underlying descriptor first if it
+    /// was evicted from the pool since the last access.
+    pub async fn write_at(&self, buf: Vec<u8>, pos: u64) -> Result<usize> {
+        self.ensure_open().await?;
+        // Extract everything the I/O needs and drop the `Ref` before
+        // `.await`ing: the borrow must not be held live across a suspension
+        // point, since another task's `ensure_open` can run during the
+        // await and pick this very file as the CLOCK victim, which takes
+        // `inner.file` with `borrow_mut()`.
+        let (reactor, fd) = {
+            let borrow = self.inner.file.borrow();
+            let file = borrow.as_ref().unwrap();
+            (file.reactor.upgrade().unwrap(), file.as_raw_fd())
+        };
+        // Pin this file's slot against CLOCK eviction for as long as `fd`
+        // is suspended below; otherwise the slot could be swept, closed,
+        // and its descriptor number handed to an unrelated concurrent
+        // `open()` while this write is still in flight on it.
+        let _pin = self.inner.begin_io();
+        let source = reactor.write_buffered(fd, buf, pos);
+        source.collect_rw().await.map_err(|source| {
+            GlommioError::create_enhanced(source, "Writing", Some(self.inner.path.as_path()), Some(fd))
+        })
+    }
+
+    /// Reads data at the specified position into a buffer allocated by this
+    /// library, reopening the underlying descriptor first if it was evicted
+    /// from the pool since the last access.
+    pub async fn read_at(&self, pos: u64, size: usize) -> Result<ReadResult> {
+        self.ensure_open().await?;
+        // See the comment in `write_at`: the `Ref` must not outlive this
+        // block, or it can be concurrently `borrow_mut()`d (and panic) by
+        // CLOCK eviction while this read is suspended across its `.await`.
+        let (reactor, fd, scheduler) = {
+            let borrow = self.inner.file.borrow();
+            let file = borrow.as_ref().unwrap();
+            (
+                file.reactor.upgrade().unwrap(),
+                file.as_raw_fd(),
+                file.scheduler.borrow().as_ref().copied(),
+            )
+        };
+        // See the comment in `write_at`: the slot must stay pinned for as
+        // long as this read is suspended on `fd`.
+        let _pin = self.inner.begin_io();
+        let source = reactor.read_buffered(fd, pos, size, scheduler.as_ref());
+        let read_size = source.collect_rw().await.map_err(|source| {
+            GlommioError::create_enhanced(source, "Reading", Some(self.inner.path.as_path()), Some(fd))
+        })?;
+        Ok(ReadResult::from_sliced_buffer(source, 0, read_size))
+    }
+
+    /// Performs a stat operation on a file, reopening the underlying
+    /// descriptor first if necessary.
+    pub async fn stat(&self) -> Result<Stat> {
+        self.ensure_open().await?;
+        // Same reasoning as `write_at`/`read_at`: don't hold `inner.file`'s
+        // `Ref` across the `.await` below.
+        let (reactor, fd) = {
+            let borrow = self.inner.file.borrow();
+            let file = borrow.as_ref().unwrap();
+            (file.reactor.upgrade().unwrap(), file.as_raw_fd())
+        };
+        // See the comment in `write_at`: pin the slot for the duration of
+        // the suspended statx.
+        let _pin = self.inner.begin_io();
+        let source = reactor.statx(fd);
+        source
+            .collect_rw()
+            .await
+            .map_err(|source| {
+                GlommioError::create_enhanced(source, "Stat", Some(self.inner.path.as_path()), Some(fd))
+            })
+            .map(Into::into)
+    }
+
+    /// Returns the path this `VirtualFile` was opened with.
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Closes this file, removing it from the pool if it currently holds a
+    /// live descriptor.
+    pub async fn close(self) -> Result<()> {
+        if let Some(slot) = self.inner.slot.get() {
+            if let Some(s) = self.inner.pool.slots.borrow_mut().get_mut(slot) {
+                s.owner = None;
+            }
+        }
+        if let Some(file) = self.inner.file.borrow_mut().take() {
+            file.close().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::make_test_directories;
+
+    macro_rules! virtual_file_test {
+        ( $name:ident, $dir:ident, $kind:ident, $code:block) => {
+            #[test]
+            fn $name() {
+                for dir in make_test_directories(&format!("virtual-file-{}", stringify!($name))) {
+                    let $dir = dir.path.clone();
+                    let $kind = dir.kind;
+                    test_executor!(async move { $code });
+                }
+            }
+        };
+    }
+
+    virtual_file_test!(create_write_read_close, path, _k, {
+        let file = VirtualFile::create(path.join("testfile")).await.unwrap();
+        let n = file.write_at(vec![1, 2, 3, 4, 5], 0).await.unwrap();
+        assert_eq!(n, 5);
+
+        let rb = file.read_at(0, 5).await.unwrap();
+        assert_eq!(&*rb, &[1, 2, 3, 4, 5]);
+
+        file.close().await.unwrap();
+
+        // Reopening sees the data that was written.
+        let file = VirtualFile::open(path.join("testfile")).await.unwrap();
+        let rb = file.read_at(0, 5).await.unwrap();
+        assert_eq!(&*rb, &[1, 2, 3, 4, 5]);
+        file.close().await.unwrap();
+    });
+
+    virtual_file_test!(eviction_transparently_reopens, path, _k, {
+        configure_max_open_files(2);
+
+        let mut files = Vec::new();
+        for i in 0..5u8 {
+            let file = VirtualFile::create(path.join(format!("file-{}", i)))
+                .await
+                .unwrap();
+            file.write_at(vec![i; 4], 0).await.unwrap();
+            files.push(file);
+        }
+
+        let before = virtual_file_stats();
+
+        // With only 2 descriptors allowed open at once, reading back all 5
+        // files in order forces CLOCK to evict and this loop to transparently
+        // reopen several of them.
+        for (i, file) in files.iter().enumerate() {
+            let rb = file.read_at(0, 4).await.unwrap();
+            assert_eq!(&*rb, &[i as u8; 4]);
+        }
+
+        let after = virtual_file_stats();
+        assert!(
+            after.files_evicted > before.files_evicted,
+            "expected at least one eviction with a 2-slot pool and 5 live files"
+        );
+        assert!(after.files_reopened > before.files_reopened);
+
+        for file in files {
+            file.close().await.unwrap();
+        }
+    });
+
+    // Drives a future manually rather than through `test_executor!`'s
+    // `spawn`, since this trimmed module doesn't expose one: polling by
+    // hand is enough to show `make_room_for` can't pick a pinned slot.
+    fn noop_waker() -> std::task::Waker {
+        fn no_op(_: *const ()) {}
+        fn clone(_: *const ()) -> std::task::RawWaker {
+            raw_waker()
+        }
+        fn raw_waker() -> std::task::RawWaker {
+            static VTABLE: std::task::RawWakerVTable =
+                std::task::RawWakerVTable::new(clone, no_op, no_op, no_op);
+            std::task::RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        unsafe { std::task::Waker::from_raw(raw_waker()) }
+    }
+
+    virtual_file_test!(eviction_skips_pinned_slot, path, _k, {
+        use std::{future::Future, task::Poll};
+
+        let pool = VirtualFilePool::new(1);
+        let resident = Rc::new(Inner {
+            path: path.join("resident"),
+            flags: 0,
+            mode: 0,
+            file: RefCell::new(None),
+            slot: Cell::new(None),
+            pool: pool.clone(),
+            inflight: Cell::new(0),
+        });
+        let slot = pool.make_room_for(&resident).await;
+        resident.slot.set(Some(slot));
+
+        // Pin the only slot, as `write_at`/`read_at`/`stat` do for the
+        // duration of their `.await`.
+        let guard = resident.begin_io();
+
+        let newcomer = Rc::new(Inner {
+            path: path.join("newcomer"),
+            flags: 0,
+            mode: 0,
+            file: RefCell::new(None),
+            slot: Cell::new(None),
+            pool: pool.clone(),
+            inflight: Cell::new(0),
+        });
+
+        let waker = noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut fut = Box::pin(pool.make_room_for(&newcomer));
+
+        // With the only slot pinned, the sweep can never find a victim and
+        // must keep yielding instead of evicting it.
+        for _ in 0..5 {
+            assert!(matches!(fut.as_mut().poll(&mut cx), Poll::Pending));
+        }
+
+        // Once the in-flight operation finishes and unpins the slot, the
+        // next sweep evicts it as normal.
+        drop(guard);
+        let got = loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(got) => break got,
+                Poll::Pending => continue,
+            }
+        };
+        assert_eq!(got, slot);
+    });
+}